@@ -0,0 +1,240 @@
+//! A second execution mode modeled on the AoC 2018 "device": a register
+//! machine with a fixed-size register array and an optional instruction
+//! pointer binding (`#ip N`).
+
+use crate::{parse_i64, ParseOpError};
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum ElfOp {
+    Addr(usize, usize, usize),
+    Addi(usize, i64, usize),
+    Mulr(usize, usize, usize),
+    Muli(usize, i64, usize),
+    Banr(usize, usize, usize),
+    Bani(usize, i64, usize),
+    Borr(usize, usize, usize),
+    Bori(usize, i64, usize),
+    Setr(usize, usize),
+    Seti(i64, usize),
+    Gtir(i64, usize, usize),
+    Gtri(usize, i64, usize),
+    Gtrr(usize, usize, usize),
+    Eqir(i64, usize, usize),
+    Eqri(usize, i64, usize),
+    Eqrr(usize, usize, usize),
+}
+
+fn parse_usize(raw: &str) -> Result<usize, ParseOpError> {
+    raw.parse::<usize>()
+        .map_err(|_| ParseOpError::new(format!("'{}' is not a register index", raw)))
+}
+
+/// The operand positions of `op` that are register indices, as opposed to
+/// immediate values, so callers can bounds-check them against the machine's
+/// register count.
+fn register_operands(op: &ElfOp) -> Vec<usize> {
+    match *op {
+        ElfOp::Addr(a, b, c)
+        | ElfOp::Mulr(a, b, c)
+        | ElfOp::Banr(a, b, c)
+        | ElfOp::Borr(a, b, c)
+        | ElfOp::Gtrr(a, b, c)
+        | ElfOp::Eqrr(a, b, c) => vec![a, b, c],
+        ElfOp::Addi(a, _, c)
+        | ElfOp::Muli(a, _, c)
+        | ElfOp::Bani(a, _, c)
+        | ElfOp::Bori(a, _, c)
+        | ElfOp::Gtri(a, _, c)
+        | ElfOp::Eqri(a, _, c)
+        | ElfOp::Setr(a, c) => vec![a, c],
+        ElfOp::Seti(_, c) => vec![c],
+        ElfOp::Gtir(_, b, c) | ElfOp::Eqir(_, b, c) => vec![b, c],
+    }
+}
+
+fn check_register_bounds(
+    op: &ElfOp,
+    register_count: usize,
+    line: usize,
+) -> Result<(), ParseOpError> {
+    for reg in register_operands(op) {
+        if reg >= register_count {
+            return Err(ParseOpError::new(format!(
+                "register {} is out of range (only {} registers)",
+                reg, register_count
+            ))
+            .at_line(line));
+        }
+    }
+    Ok(())
+}
+
+impl std::str::FromStr for ElfOp {
+    type Err = ParseOpError;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let mut parts = raw.split_whitespace();
+        let op_type = parts
+            .next()
+            .ok_or_else(|| ParseOpError::new("missing opcode".to_string()))?;
+        let args: Vec<&str> = parts.collect();
+        let (a, b, c) = match args.as_slice() {
+            [a, b, c] => (*a, *b, *c),
+            _ => {
+                return Err(ParseOpError::new(format!(
+                    "'{}' needs exactly three operands",
+                    raw
+                )))
+            }
+        };
+        let c = parse_usize(c)?;
+
+        match op_type {
+            "addr" => Ok(ElfOp::Addr(parse_usize(a)?, parse_usize(b)?, c)),
+            "addi" => Ok(ElfOp::Addi(parse_usize(a)?, parse_i64(b)?, c)),
+            "mulr" => Ok(ElfOp::Mulr(parse_usize(a)?, parse_usize(b)?, c)),
+            "muli" => Ok(ElfOp::Muli(parse_usize(a)?, parse_i64(b)?, c)),
+            "banr" => Ok(ElfOp::Banr(parse_usize(a)?, parse_usize(b)?, c)),
+            "bani" => Ok(ElfOp::Bani(parse_usize(a)?, parse_i64(b)?, c)),
+            "borr" => Ok(ElfOp::Borr(parse_usize(a)?, parse_usize(b)?, c)),
+            "bori" => Ok(ElfOp::Bori(parse_usize(a)?, parse_i64(b)?, c)),
+            "setr" => Ok(ElfOp::Setr(parse_usize(a)?, c)),
+            "seti" => Ok(ElfOp::Seti(parse_i64(a)?, c)),
+            "gtir" => Ok(ElfOp::Gtir(parse_i64(a)?, parse_usize(b)?, c)),
+            "gtri" => Ok(ElfOp::Gtri(parse_usize(a)?, parse_i64(b)?, c)),
+            "gtrr" => Ok(ElfOp::Gtrr(parse_usize(a)?, parse_usize(b)?, c)),
+            "eqir" => Ok(ElfOp::Eqir(parse_i64(a)?, parse_usize(b)?, c)),
+            "eqri" => Ok(ElfOp::Eqri(parse_usize(a)?, parse_i64(b)?, c)),
+            "eqrr" => Ok(ElfOp::Eqrr(parse_usize(a)?, parse_usize(b)?, c)),
+            _ => Err(ParseOpError::new(format!(
+                "'{}' is not a recognized elfcode opcode",
+                op_type
+            ))),
+        }
+    }
+}
+
+/// A device program: its instructions, and the register (if any) bound to
+/// the instruction pointer by a `#ip N` header line.
+#[derive(Debug)]
+pub(crate) struct ElfProgram {
+    pub(crate) ip_register: Option<usize>,
+    pub(crate) instructions: Vec<ElfOp>,
+}
+
+pub(crate) fn parse_program(
+    raw_input: &str,
+    register_count: usize,
+) -> Result<ElfProgram, ParseOpError> {
+    let mut ip_register = None;
+    let mut instructions = Vec::new();
+
+    for (i, line) in raw_input.lines().enumerate() {
+        let line_number = i + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(reg) = line.strip_prefix("#ip ") {
+            let reg = parse_usize(reg.trim()).map_err(|e| e.at_line(line_number))?;
+            if reg >= register_count {
+                return Err(ParseOpError::new(format!(
+                    "register {} is out of range (only {} registers)",
+                    reg, register_count
+                ))
+                .at_line(line_number));
+            }
+            ip_register = Some(reg);
+        } else {
+            let op = line.parse::<ElfOp>().map_err(|e| e.at_line(line_number))?;
+            check_register_bounds(&op, register_count, line_number)?;
+            instructions.push(op);
+        }
+    }
+
+    Ok(ElfProgram {
+        ip_register,
+        instructions,
+    })
+}
+
+/// A register machine that runs an `ElfProgram`.
+pub(crate) struct ElfMachine {
+    registers: Vec<i64>,
+    program: ElfProgram,
+}
+
+impl ElfMachine {
+    pub(crate) fn new(program: ElfProgram, register_count: usize) -> Self {
+        ElfMachine {
+            registers: vec![0; register_count],
+            program,
+        }
+    }
+
+    fn apply(&mut self, op: ElfOp) {
+        let regs = &self.registers;
+        let (c, value) = match op {
+            ElfOp::Addr(a, b, c) => (c, regs[a] + regs[b]),
+            ElfOp::Addi(a, b, c) => (c, regs[a] + b),
+            ElfOp::Mulr(a, b, c) => (c, regs[a] * regs[b]),
+            ElfOp::Muli(a, b, c) => (c, regs[a] * b),
+            ElfOp::Banr(a, b, c) => (c, regs[a] & regs[b]),
+            ElfOp::Bani(a, b, c) => (c, regs[a] & b),
+            ElfOp::Borr(a, b, c) => (c, regs[a] | regs[b]),
+            ElfOp::Bori(a, b, c) => (c, regs[a] | b),
+            ElfOp::Setr(a, c) => (c, regs[a]),
+            ElfOp::Seti(a, c) => (c, a),
+            ElfOp::Gtir(a, b, c) => (c, (a > regs[b]) as i64),
+            ElfOp::Gtri(a, b, c) => (c, (regs[a] > b) as i64),
+            ElfOp::Gtrr(a, b, c) => (c, (regs[a] > regs[b]) as i64),
+            ElfOp::Eqir(a, b, c) => (c, (a == regs[b]) as i64),
+            ElfOp::Eqri(a, b, c) => (c, (regs[a] == b) as i64),
+            ElfOp::Eqrr(a, b, c) => (c, (regs[a] == regs[b]) as i64),
+        };
+        self.registers[c] = value;
+    }
+
+    /// Run until the instruction pointer runs off the program, or
+    /// `max_cycles` instructions have executed, whichever comes first.
+    /// Returns the final register state either way.
+    pub(crate) fn run(&mut self, max_cycles: u64) -> Vec<i64> {
+        let mut ip: i64 = 0;
+        let mut cycles = 0;
+
+        while ip >= 0 && (ip as usize) < self.program.instructions.len() && cycles < max_cycles {
+            if let Some(ip_reg) = self.program.ip_register {
+                self.registers[ip_reg] = ip;
+            }
+            self.apply(self.program.instructions[ip as usize]);
+            ip = match self.program.ip_register {
+                Some(ip_reg) => self.registers[ip_reg],
+                None => ip,
+            };
+            ip += 1;
+            cycles += 1;
+        }
+
+        self.registers.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_PROGRAM: &str = "#ip 0\nseti 5 0 1\nseti 6 0 2\naddi 0 1 0\naddr 1 2 3\nsetr 1 0 0\nseti 8 0 4\nseti 9 0 5\n";
+
+    #[test]
+    fn runs_a_program_with_a_bound_instruction_pointer() {
+        let program = parse_program(SAMPLE_PROGRAM, 6).unwrap();
+        let registers = ElfMachine::new(program, 6).run(100);
+        assert_eq!(registers, vec![6, 5, 6, 0, 0, 9]);
+    }
+
+    #[test]
+    fn rejects_an_out_of_range_register_with_its_line_number() {
+        let err = parse_program("#ip 0\naddr 99 0 0\n", 6).unwrap_err();
+        assert_eq!(err.line, 2);
+    }
+}
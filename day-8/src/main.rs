@@ -1,32 +1,274 @@
-use regex::Regex;
-use std::collections::HashMap;
+mod elfcode;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
 use std::fs::read_to_string;
+use std::str::FromStr;
 
-#[derive(Debug)]
-enum OP {
+use elfcode::ElfMachine;
+
+const ELFCODE_REGISTER_COUNT: usize = 6;
+const DEFAULT_MAX_CYCLES: u64 = 10_000_000;
+
+/// An operand that is either a literal number or a register name, resolved
+/// at execution time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Value {
+    Literal(i64),
+    Register(char),
+}
+
+impl Value {
+    fn resolve(&self, registers: &HashMap<char, i64>) -> i64 {
+        match self {
+            Value::Literal(num) => *num,
+            Value::Register(reg) => *registers.get(reg).unwrap_or(&0),
+        }
+    }
+}
+
+impl FromStr for Value {
+    type Err = ParseOpError;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        match parse_i64(raw) {
+            Ok(num) => Ok(Value::Literal(num)),
+            Err(_) => Ok(Value::Register(parse_register(raw)?)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum OP {
     Nop(i64),
     Acc(i64),
     Jmp(i64),
+    Set(char, Value),
+    Add(char, Value),
+    Mul(char, Value),
+    Mod(char, Value),
+    Snd(Value),
+    Rcv(char),
+    Jgz(Value, Value),
+}
+
+/// A malformed instruction, naming the line that couldn't be parsed.
+#[derive(Debug)]
+pub(crate) struct ParseOpError {
+    pub(crate) line: usize,
+    pub(crate) reason: String,
+}
+
+impl ParseOpError {
+    pub(crate) fn new(reason: String) -> Self {
+        ParseOpError { line: 0, reason }
+    }
+
+    /// Fill in the line number once the caller knows which line this came from.
+    pub(crate) fn at_line(mut self, line: usize) -> Self {
+        self.line = line;
+        self
+    }
+}
+
+impl fmt::Display for ParseOpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+impl std::error::Error for ParseOpError {}
+
+pub(crate) fn parse_i64(raw: &str) -> Result<i64, ParseOpError> {
+    raw.trim_start_matches('+')
+        .parse::<i64>()
+        .map_err(|_| ParseOpError::new(format!("'{}' is not a valid number", raw)))
 }
 
-fn to_int(value: &str) -> i64 {
-    match value.parse::<i64>() {
-        Ok(i) => i,
-        Err(_e) => 0,
+pub(crate) fn parse_register(raw: &str) -> Result<char, ParseOpError> {
+    let mut chars = raw.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c.is_alphabetic() => Ok(c),
+        _ => Err(ParseOpError::new(format!("'{}' is not a register", raw))),
     }
 }
 
-fn to_operation(raw_instruction: regex::Captures) -> OP {
-    // ex: map "acc +1" -> OP::Acc
-    let op_type = &raw_instruction[1];
-    let num = to_int(&raw_instruction[2]);
-    match op_type {
-        "acc" => OP::Acc(num),
-        "jmp" => OP::Jmp(num),
-        _ => OP::Nop(num),
+impl FromStr for OP {
+    type Err = ParseOpError;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let mut parts = raw.split_whitespace();
+        let op_type = parts
+            .next()
+            .ok_or_else(|| ParseOpError::new("missing opcode".to_string()))?;
+        let args: Vec<&str> = parts.collect();
+
+        match (op_type, args.as_slice()) {
+            ("acc", [num]) => Ok(OP::Acc(parse_i64(num)?)),
+            ("jmp", [num]) => Ok(OP::Jmp(parse_i64(num)?)),
+            ("nop", [num]) => Ok(OP::Nop(parse_i64(num)?)),
+            ("set", [reg, val]) => Ok(OP::Set(parse_register(reg)?, val.parse()?)),
+            ("add", [reg, val]) => Ok(OP::Add(parse_register(reg)?, val.parse()?)),
+            ("mul", [reg, val]) => Ok(OP::Mul(parse_register(reg)?, val.parse()?)),
+            ("mod", [reg, val]) => Ok(OP::Mod(parse_register(reg)?, val.parse()?)),
+            ("snd", [val]) => Ok(OP::Snd(val.parse()?)),
+            ("rcv", [reg]) => Ok(OP::Rcv(parse_register(reg)?)),
+            ("jgz", [cond, offset]) => Ok(OP::Jgz(cond.parse()?, offset.parse()?)),
+            _ => Err(ParseOpError::new(format!(
+                "'{}' is not a recognized instruction",
+                raw
+            ))),
+        }
     }
 }
 
+/// Outcome of running an `Interpreter` to completion.
+#[derive(Debug, PartialEq)]
+pub(crate) enum ProgramResult {
+    /// The program ran off the end of memory, carrying the final accumulator.
+    Terminated(i64),
+    /// The program re-entered a state (instruction index plus register file)
+    /// it had already been in, carrying the accumulator at the moment the
+    /// loop was detected.
+    Looped(i64),
+}
+
+/// A small register VM, generalized from the Day 8 accumulator machine into
+/// the style of the AoC 2017 "Duet" assembly. `acc`/`jmp`/`nop` still work as
+/// plain aliases; `set`/`add`/`mul`/`mod`/`jgz` operate on a register file,
+/// `snd` records the last "sound" played, and `rcv` of a nonzero register
+/// halts the program, leaving that sound readable through `last_sound`.
+pub(crate) struct Interpreter {
+    idx: u32,
+    accumulator: i64,
+    memory: Vec<OP>,
+    registers: HashMap<char, i64>,
+    last_sound: Option<i64>,
+}
+
+impl Interpreter {
+    pub(crate) fn new(memory: Vec<OP>) -> Self {
+        Interpreter {
+            idx: 0,
+            accumulator: 0,
+            memory,
+            registers: HashMap::new(),
+            last_sound: None,
+        }
+    }
+
+    pub(crate) fn register(&self, reg: char) -> i64 {
+        *self.registers.get(&reg).unwrap_or(&0)
+    }
+
+    pub(crate) fn last_sound(&self) -> Option<i64> {
+        self.last_sound
+    }
+
+    /// A snapshot of everything that determines future behavior: the
+    /// instruction about to run, plus the register file. Revisiting an
+    /// identical snapshot means the program is in a true infinite loop —
+    /// unlike `idx` alone, which an ordinary counting loop can revisit many
+    /// times while still converging.
+    fn state(&self) -> (u32, Vec<(char, i64)>) {
+        let mut registers: Vec<(char, i64)> = self.registers.iter().map(|(&r, &v)| (r, v)).collect();
+        registers.sort_unstable();
+        (self.idx, registers)
+    }
+
+    /// Advance exactly one instruction.
+    pub(crate) fn step(&mut self) {
+        let op = self.memory[self.idx as usize];
+        self.idx = match op {
+            OP::Acc(num) => {
+                self.accumulator += num;
+                normal_next_index(op, self.idx)
+            }
+            OP::Jmp(_) | OP::Nop(_) => normal_next_index(op, self.idx),
+            OP::Set(reg, val) => {
+                let resolved = val.resolve(&self.registers);
+                self.registers.insert(reg, resolved);
+                normal_next_index(op, self.idx)
+            }
+            OP::Add(reg, val) => {
+                let resolved = val.resolve(&self.registers);
+                *self.registers.entry(reg).or_insert(0) += resolved;
+                normal_next_index(op, self.idx)
+            }
+            OP::Mul(reg, val) => {
+                let resolved = val.resolve(&self.registers);
+                *self.registers.entry(reg).or_insert(0) *= resolved;
+                normal_next_index(op, self.idx)
+            }
+            OP::Mod(reg, val) => {
+                let resolved = val.resolve(&self.registers);
+                *self.registers.entry(reg).or_insert(0) %= resolved;
+                normal_next_index(op, self.idx)
+            }
+            OP::Snd(val) => {
+                self.last_sound = Some(val.resolve(&self.registers));
+                normal_next_index(op, self.idx)
+            }
+            OP::Rcv(reg) => {
+                if self.register(reg) != 0 {
+                    self.memory.len() as u32
+                } else {
+                    normal_next_index(op, self.idx)
+                }
+            }
+            OP::Jgz(cond, offset) => {
+                if cond.resolve(&self.registers) > 0 {
+                    jump_index(self.idx, offset.resolve(&self.registers))
+                } else {
+                    normal_next_index(op, self.idx)
+                }
+            }
+        };
+    }
+
+    /// Run to completion, mutating the register file and accumulator as it goes.
+    pub(crate) fn run(&mut self) -> ProgramResult {
+        let mut visited = HashSet::new();
+
+        loop {
+            if self.idx as usize >= self.memory.len() {
+                break ProgramResult::Terminated(self.accumulator);
+            } else if !visited.insert(self.state()) {
+                break ProgramResult::Looped(self.accumulator);
+            }
+            self.step();
+        }
+    }
+
+    /// Like `run`, but prints `idx`, the opcode, and the accumulator at every
+    /// cycle. Useful for debugging a program that misbehaves.
+    pub(crate) fn run_with_trace(&mut self) -> ProgramResult {
+        let mut visited = HashSet::new();
+
+        loop {
+            if self.idx as usize >= self.memory.len() {
+                break ProgramResult::Terminated(self.accumulator);
+            } else if !visited.insert(self.state()) {
+                break ProgramResult::Looped(self.accumulator);
+            }
+            println!(
+                "idx={} op={:?} acc={}",
+                self.idx, self.memory[self.idx as usize], self.accumulator
+            );
+            self.step();
+        }
+    }
+}
+
+fn parse_instructions(raw_input: &str) -> Result<Vec<OP>, ParseOpError> {
+    raw_input
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| line.parse::<OP>().map_err(|e| e.at_line(i + 1)))
+        .collect()
+}
+
 fn jump_index(index: u32, change: i64) -> u32 {
     match change + index as i64 {
         index if index >= 0 => index as u32,
@@ -38,116 +280,211 @@ fn nop_index(index: u32) -> u32 {
     index + 1
 }
 
-fn accumulate_from_instructions(
-    instructions: &Vec<OP>,
-    swap_op_index: Option<u32>,
-) -> (i64, Option<u32>, HashMap<u32, u32>) {
-    let mut visited_instruction_graph: HashMap<u32, u32> = HashMap::new();
-    let mut acc = 0;
-    let mut index = 0;
+/// Where control flow goes after `op` by default — ignoring register state
+/// and any swap. This is what `jmp` does, and what everything else (`acc`,
+/// `nop`, and the duet opcodes, none of which show up in a Day 8 program)
+/// falls back to. Shared by `Interpreter::step` and the swap-repair
+/// algorithm below so they can't drift out of sync on opcode changes.
+fn normal_next_index(op: OP, index: u32) -> u32 {
+    match op {
+        OP::Jmp(num) => jump_index(index, num),
+        _ => nop_index(index),
+    }
+}
 
-    loop {
-        if let Some(_) = visited_instruction_graph.get(&index) {
-            // if we've already been here before, it's an infinite loop!
-            // break out with the index we looped back to
-            break (acc, Some(index), visited_instruction_graph);
-        } else if index >= instructions.len() as u32 {
-            // if we exceed the length of the array, then we terminated successfully!
-            // break out with the final acc value
-            break (acc, None, visited_instruction_graph);
-        } else {
-            let next_index = match instructions[index as usize] {
-                OP::Acc(num) => {
-                    acc += num;
-                    index + 1
-                }
-                OP::Jmp(num) => match swap_op_index {
-                    // if we're attempting to "swap" a faulty instruction,
-                    // switch to the nop behavior for this one
-                    Some(swap_index) if swap_index == index => nop_index(index),
-                    _ => jump_index(index, num),
-                },
-                OP::Nop(num) => match swap_op_index {
-                    // vice versa for this faulty instruction
-                    Some(swap_index) if swap_index == index => jump_index(index, num),
-                    _ => nop_index(index),
-                },
+/// Index of the instruction that executes after `index` under *normal*
+/// (unswapped) semantics.
+fn next_normal(memory: &[OP], index: u32) -> u32 {
+    normal_next_index(memory[index as usize], index)
+}
+
+/// Find the single `jmp`/`nop` swap that lets `memory` terminate, in O(n).
+///
+/// Build a reverse map of `next_normal(i) -> i` edges, with a virtual "end"
+/// node at `memory.len()`, and walk it backward from "end" to find every
+/// instruction whose normal execution path reaches termination
+/// (`can_terminate`), recording the accumulator still picked up between
+/// that instruction and the end. Then drive an `Interpreter` forward along
+/// the normal path from instruction 0: the first `jmp`/`nop` whose *swapped*
+/// successor is in `can_terminate` is the broken instruction, and the answer
+/// is the interpreter's accumulator at that point plus the tail we already
+/// summed for its swapped successor. Stepping is delegated to
+/// `Interpreter::step` rather than re-matching on `OP` here, so this can't
+/// drift from the real fetch-execute semantics.
+fn accumulate_and_fix_broken_instruction(memory: &[OP]) -> i64 {
+    let len = memory.len() as u32;
+
+    let mut reverse_adj: HashMap<u32, Vec<u32>> = HashMap::new();
+    for index in 0..len {
+        reverse_adj
+            .entry(next_normal(memory, index))
+            .or_default()
+            .push(index);
+    }
+
+    let mut can_terminate: HashMap<u32, i64> = HashMap::new();
+    can_terminate.insert(len, 0);
+    let mut frontier = VecDeque::from([len]);
+    while let Some(node) = frontier.pop_front() {
+        let tail_acc = can_terminate[&node];
+        for &pred in reverse_adj.get(&node).into_iter().flatten() {
+            if can_terminate.contains_key(&pred) {
+                continue;
+            }
+            let contributed = match memory[pred as usize] {
+                OP::Acc(num) => num,
+                _ => 0,
             };
-            visited_instruction_graph.insert(index, next_index);
-            index = next_index;
+            can_terminate.insert(pred, tail_acc + contributed);
+            frontier.push_back(pred);
         }
     }
-}
 
-fn get_possibly_broken_instructions(
-    visited_instruction_graph: &HashMap<u32, u32>,
-    initial_index: u32,
-) -> Vec<u32> {
-    let mut index = initial_index;
-    let mut possibly_broken_instructions: Vec<u32> = Vec::new();
+    let mut interpreter = Interpreter::new(memory.to_vec());
+    let mut visited = vec![false; len as usize];
     loop {
-        let next_index = *visited_instruction_graph.get(&index).unwrap();
-        possibly_broken_instructions.push(index);
-        if next_index == initial_index {
-            break; // we've closed the loop!
-        } else {
-            index = next_index;
+        let idx = interpreter.idx;
+        if idx >= len {
+            // it already terminates cleanly, no fix needed
+            return interpreter.accumulator;
         }
-    }
-    possibly_broken_instructions
-}
-
-fn accumulate_and_fix_broken_instruction(instructions: &Vec<OP>) -> i64 {
-    let (acc, finished_early_at_index, visited_instruction_graph) =
-        accumulate_from_instructions(instructions, None);
-    match finished_early_at_index {
-        // if we didn't finish early, we got it right the first try
-        None => acc,
-        Some(initial_index) => {
-            let possibly_broken_instructions =
-                get_possibly_broken_instructions(&visited_instruction_graph, initial_index);
-            let mut index = 0;
-            loop {
-                let instruction_index = possibly_broken_instructions[index];
-                match instructions[instruction_index as usize] {
-                    // if it's an accumulation function, it couldn't *possibly* be the error
-                    OP::Acc(_) => (),
-                    // otherwise, let's try running the accumulator again
-                    // swapping the Nop for Jmp (or vice versa)
-                    _ => {
-                        let (acc, finished_early_at_index, _) =
-                            accumulate_from_instructions(instructions, Some(instruction_index));
-                        match finished_early_at_index {
-                            Some(_) => (),
-                            // if we didn't finish early, we can break from the loop!
-                            None => break acc,
-                        }
-                    }
+        assert!(
+            !visited[idx as usize],
+            "no single instruction swap fixes this program"
+        );
+        visited[idx as usize] = true;
+        match memory[idx as usize] {
+            OP::Jmp(_) => {
+                if let Some(&tail_acc) = can_terminate.get(&nop_index(idx)) {
+                    return interpreter.accumulator + tail_acc;
+                }
+            }
+            OP::Nop(num) => {
+                if let Some(&tail_acc) = can_terminate.get(&jump_index(idx, num)) {
+                    return interpreter.accumulator + tail_acc;
                 }
-                index += 1;
             }
+            // the duet opcodes never show up in the Day 8 input
+            _ => {}
         }
+        interpreter.step();
     }
 }
 
-fn main() {
-    let read_instructions = Regex::new(r"(acc|nop|jmp) ([\+|\-][0-9]+)").unwrap();
+fn max_cycles_arg(args: &[String]) -> u64 {
+    args.iter()
+        .position(|arg| arg == "--max-cycles")
+        .and_then(|i| args.get(i + 1))
+        .and_then(|raw| raw.parse().ok())
+        .unwrap_or(DEFAULT_MAX_CYCLES)
+}
+
+fn run_duet(args: &[String]) -> Result<(), ParseOpError> {
+    let raw_input = read_to_string("duet.txt");
+
+    match raw_input {
+        Ok(raw_input) => {
+            let instructions = parse_instructions(&raw_input)?;
+            let mut interpreter = Interpreter::new(instructions);
+            let result = if args.iter().any(|arg| arg == "--trace") {
+                interpreter.run_with_trace()
+            } else {
+                interpreter.run()
+            };
+            println!(
+                "Duet program {:?}, last sound {:?}",
+                result,
+                interpreter.last_sound()
+            );
+            Ok(())
+        }
+        Err(_) => {
+            println!("Something's wrong with the input file!");
+            Ok(())
+        }
+    }
+}
+
+fn run_elfcode(args: &[String]) -> Result<(), ParseOpError> {
+    let raw_input = read_to_string("elfcode.txt");
+
+    match raw_input {
+        Ok(raw_input) => {
+            let program = elfcode::parse_program(&raw_input, ELFCODE_REGISTER_COUNT)?;
+            let registers = ElfMachine::new(program, ELFCODE_REGISTER_COUNT)
+                .run(max_cycles_arg(args));
+            println!("Final registers: {:?}", registers);
+            Ok(())
+        }
+        Err(_) => {
+            println!("Something's wrong with the input file!");
+            Ok(())
+        }
+    }
+}
+
+fn run(args: &[String]) -> Result<(), ParseOpError> {
+    if args.iter().any(|arg| arg == "--elfcode") {
+        return run_elfcode(args);
+    }
+    if args.iter().any(|arg| arg == "--duet") {
+        return run_duet(args);
+    }
+
     let raw_input = read_to_string("instructions.txt");
 
     match raw_input {
         Ok(raw_input) => {
-            let instructions: Vec<OP> = read_instructions
-                // get all the capture groups we found
-                .captures_iter(&raw_input)
-                // map each group to a shiny enum we can work with
-                .map(|instruction| to_operation(instruction))
-                .collect();
+            let instructions = parse_instructions(&raw_input)?;
 
             println!(
                 "Our accumulator hit {}",
                 accumulate_and_fix_broken_instruction(&instructions)
             );
+            Ok(())
+        }
+        Err(_) => {
+            println!("Something's wrong with the input file!");
+            Ok(())
         }
-        Err(_) => println!("Something's wrong with the input file!"),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if let Err(err) = run(&args) {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE_PROGRAM: &str = "nop +0\nacc +1\njmp +4\nacc +3\njmp -3\nacc -99\nacc +1\njmp -4\nacc +6\n";
+
+    #[test]
+    fn fixes_the_single_broken_instruction() {
+        let memory = parse_instructions(EXAMPLE_PROGRAM).unwrap();
+        assert_eq!(accumulate_and_fix_broken_instruction(&memory), 8);
+    }
+
+    #[test]
+    fn duet_program_reports_its_last_sound() {
+        let memory = parse_instructions(
+            "set a 1\nadd a 2\nmul a a\nmod a 5\nsnd a\nset a 0\nrcv a\njgz a -1\nset a 1\njgz a -2\n",
+        )
+        .unwrap();
+        let mut interpreter = Interpreter::new(memory);
+        assert_eq!(interpreter.run(), ProgramResult::Terminated(0));
+        assert_eq!(interpreter.last_sound(), Some(4));
+    }
+
+    #[test]
+    fn a_converging_counting_loop_is_not_mistaken_for_an_infinite_one() {
+        let memory = parse_instructions("set a 3\nadd a -1\njgz a -1\n").unwrap();
+        let mut interpreter = Interpreter::new(memory);
+        assert_eq!(interpreter.run(), ProgramResult::Terminated(0));
     }
 }